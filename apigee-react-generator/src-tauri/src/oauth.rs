@@ -1,4 +1,7 @@
 use std::sync::Arc;
+use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use sha2::{Digest, Sha256};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::sync::oneshot;
@@ -11,76 +14,454 @@ pub struct OAuthCallbackResult {
     pub error_description: Option<String>,
 }
 
-/// Start the OAuth callback server and return the port
+/// PKCE pair and anti-CSRF state for a single OAuth flow, returned to the
+/// caller when the flow starts. `code_verifier` must be kept for the later
+/// token exchange; `state` and `session_id` must be echoed back to
+/// `wait_for_oauth_callback`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OAuthServerSession {
+    pub session_id: String,
+    pub port: u16,
+    /// The loopback redirect URI to hand to the authorization server, e.g.
+    /// `http://127.0.0.1:51234/callback` or, with `use_tls`, `https://...`.
+    pub redirect_uri: String,
+    pub state: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub code_verifier: String,
+}
+
+/// A listener bound by `start_oauth_server`, plus the TLS acceptor to wrap
+/// incoming connections with when the caller requested `https`, and the
+/// branding/template overrides to render the callback pages with.
+struct OAuthListenerEntry {
+    listener: TcpListener,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    theme: PageTheme,
+    templates: PageTemplates,
+    max_request_bytes: usize,
+    created_at: std::time::Instant,
+}
+
+/// How long a listener may sit in `OAuthListenerRegistry` without
+/// `wait_for_oauth_callback` claiming it before it's swept away as abandoned
+/// (e.g. the user closed the auth window, or the app restarted the flow).
+const SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Tracks listeners that have already been bound by `start_oauth_server`,
+/// keyed by session id, so `wait_for_oauth_callback` can take over the
+/// already-bound listener instead of racing to re-bind the port. Registered
+/// with Tauri via `.manage(OAuthListenerRegistry::default())`.
+///
+/// Entries are swept on a `SESSION_TTL` expiry (checked opportunistically on
+/// every `start_oauth_server` call) and can also be dropped immediately via
+/// `cancel_oauth_server`, so an abandoned flow doesn't leak an open loopback
+/// socket for the life of the process.
+#[derive(Default)]
+pub struct OAuthListenerRegistry(tokio::sync::Mutex<std::collections::HashMap<String, OAuthListenerEntry>>);
+
+const UNRESERVED_CHARS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+
+/// Generate a PKCE `code_verifier`: 43-128 random unreserved characters (RFC 7636).
+fn generate_code_verifier() -> String {
+    let mut rng = rand::thread_rng();
+    (0..128)
+        .map(|_| UNRESERVED_CHARS[rng.gen_range(0..UNRESERVED_CHARS.len())] as char)
+        .collect()
+}
+
+/// Derive the PKCE `code_challenge` (method `S256`) from a `code_verifier`.
+fn generate_code_challenge(code_verifier: &str) -> String {
+    let digest = Sha256::digest(code_verifier.as_bytes());
+    general_purpose::URL_SAFE_NO_PAD.encode(digest)
+}
+
+/// Generate a random anti-CSRF `state` value.
+fn generate_state() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32)
+        .map(|_| UNRESERVED_CHARS[rng.gen_range(0..UNRESERVED_CHARS.len())] as char)
+        .collect()
+}
+
+/// Generate a random id identifying a listener held in `OAuthListenerRegistry`.
+fn generate_session_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..16)
+        .map(|_| UNRESERVED_CHARS[rng.gen_range(0..UNRESERVED_CHARS.len())] as char)
+        .collect()
+}
+
+/// Constant-time string comparison, used to check the returned `state`
+/// without leaking timing information about where it first differs.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+/// Build a `TlsAcceptor` backed by a fresh, in-memory self-signed certificate
+/// for `127.0.0.1`/`localhost`, used to serve the callback over `https`.
+fn build_tls_acceptor() -> Result<tokio_rustls::TlsAcceptor, String> {
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string(), "localhost".to_string()])
+        .map_err(|e| format!("Failed to generate self-signed certificate: {}", e))?;
+
+    let cert_der = rustls::pki_types::CertificateDer::from(cert.cert.der().to_vec());
+    let key_der = rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der())
+        .map_err(|e| format!("Failed to encode certificate private key: {}", e))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der], key_der)
+        .map_err(|e| format!("Failed to build TLS server config: {}", e))?;
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Default maximum number of bytes read from a single callback request before
+/// it is rejected with a `413`, guarding against oversized clients. Callers
+/// can override this via `start_oauth_server`'s `max_request_bytes` parameter.
+const DEFAULT_MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a single accepted connection may go without completing its
+/// request before it is abandoned, so a client that opens a socket and never
+/// sends (or stalls mid-request) can't block the accept loop indefinitely.
+const CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Failure modes of [`read_http_request`]
+enum ReadRequestError {
+    /// The request exceeded `max_size` before the header terminator was seen
+    TooLarge,
+    Io(std::io::Error),
+}
+
+/// Read incrementally from `socket` into a growable buffer until the
+/// `\r\n\r\n` header terminator is seen, or bail once `max_size` bytes have
+/// been read without finding one.
+async fn read_http_request<S>(socket: &mut S, max_size: usize) -> Result<String, ReadRequestError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let n = socket.read(&mut chunk).await.map_err(ReadRequestError::Io)?;
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+
+        if buffer.len() > max_size {
+            return Err(ReadRequestError::TooLarge);
+        }
+
+        if buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Read one HTTP request off `socket`, respond with the appropriate success,
+/// error, `413`, `404` or `204` response, and report the outcome. Returns
+/// `None` when the connection should be ignored and the accept loop should
+/// keep waiting (a transient I/O error, a timeout, or a request that isn't the
+/// callback), `Some(result)` when the flow should resolve.
+/// Bounded by `CONNECTION_TIMEOUT` so a slow or never-terminating client
+/// can't hold the connection (or the listener, when not run concurrently)
+/// open forever.
+async fn handle_connection<S>(
+    socket: S,
+    expected_state: &str,
+    theme: &PageTheme,
+    templates: &PageTemplates,
+    max_request_bytes: usize,
+) -> Option<OAuthCallbackResult>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    match tokio::time::timeout(
+        CONNECTION_TIMEOUT,
+        handle_connection_inner(socket, expected_state, theme, templates, max_request_bytes),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            log::warn!(
+                "OAuth callback connection timed out after {:?} without completing a request",
+                CONNECTION_TIMEOUT
+            );
+            None
+        }
+    }
+}
+
+async fn handle_connection_inner<S>(
+    mut socket: S,
+    expected_state: &str,
+    theme: &PageTheme,
+    templates: &PageTemplates,
+    max_request_bytes: usize,
+) -> Option<OAuthCallbackResult>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let request = match read_http_request(&mut socket, max_request_bytes).await {
+        Ok(request) => request,
+        Err(ReadRequestError::TooLarge) => {
+            let body = get_error_html(theme, templates, "Request too large");
+            let response = format!(
+                "HTTP/1.1 413 Payload Too Large\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+
+            return Some(OAuthCallbackResult {
+                code: None,
+                error: Some("Request too large".to_string()),
+                error_description: Some(format!(
+                    "The callback request exceeded the {} byte limit",
+                    max_request_bytes
+                )),
+            });
+        }
+        Err(ReadRequestError::Io(e)) => {
+            log::error!("Failed to read from socket: {}", e);
+            return None;
+        }
+    };
+
+    log::debug!("Received OAuth callback request: {}", request);
+
+    // Parse the request to extract the path and query string
+    let result = match parse_oauth_callback(&request, expected_state) {
+        Ok(result) => result,
+        Err(NotCallbackReason::WrongPath) => {
+            let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+            return None;
+        }
+        Err(NotCallbackReason::MissingCallbackParams) => {
+            let response = "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+            return None;
+        }
+    };
+
+    // Send HTML response
+    let (status, body) = if result.code.is_some() {
+        ("200 OK", get_success_html(theme, templates))
+    } else {
+        (
+            "400 Bad Request",
+            get_error_html(theme, templates, result.error.as_deref().unwrap_or("Unknown error")),
+        )
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        log::error!("Failed to write response: {}", e);
+    }
+    let _ = socket.shutdown().await;
+
+    Some(result)
+}
+
+/// Start the OAuth callback server: binds the listener immediately (so the
+/// port we hand back is guaranteed to be the one we're listening on, with no
+/// TOCTOU window for another process to steal it), stashes it in managed
+/// state under a fresh session id, and returns a PKCE/state session. When
+/// `use_tls` is set, connections are served over an ephemeral self-signed
+/// certificate and the returned `redirect_uri` uses the `https` scheme.
 #[tauri::command]
-pub async fn start_oauth_server() -> Result<u16, String> {
-    // Find an available port
-    let port = portpicker::pick_unused_port().ok_or("No available port found")?;
-    Ok(port)
+pub async fn start_oauth_server(
+    use_tls: bool,
+    theme: Option<PageTheme>,
+    templates: Option<PageTemplates>,
+    max_request_bytes: Option<usize>,
+    registry: tauri::State<'_, OAuthListenerRegistry>,
+) -> Result<OAuthServerSession, String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| format!("Failed to bind loopback listener: {}", e))?;
+    let port = listener
+        .local_addr()
+        .map_err(|e| format!("Failed to read bound listener address: {}", e))?
+        .port();
+
+    let tls_acceptor = if use_tls {
+        Some(build_tls_acceptor()?)
+    } else {
+        None
+    };
+
+    let session_id = generate_session_id();
+    {
+        let mut sessions = registry.0.lock().await;
+        sessions.retain(|id, entry| {
+            let expired = entry.created_at.elapsed() >= SESSION_TTL;
+            if expired {
+                log::info!("Dropping abandoned OAuth callback session {}", id);
+            }
+            !expired
+        });
+        sessions.insert(
+            session_id.clone(),
+            OAuthListenerEntry {
+                listener,
+                tls_acceptor,
+                theme: theme.unwrap_or_default(),
+                templates: templates.unwrap_or_default(),
+                max_request_bytes: max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_SIZE),
+                created_at: std::time::Instant::now(),
+            },
+        );
+    }
+
+    let code_verifier = generate_code_verifier();
+    let code_challenge = generate_code_challenge(&code_verifier);
+    let state = generate_state();
+    let scheme = if use_tls { "https" } else { "http" };
+
+    Ok(OAuthServerSession {
+        session_id,
+        port,
+        redirect_uri: format!("{}://127.0.0.1:{}/callback", scheme, port),
+        state,
+        code_challenge,
+        code_challenge_method: "S256".to_string(),
+        code_verifier,
+    })
 }
 
-/// Wait for the OAuth callback on the specified port
+/// Wait for the OAuth callback on the listener bound by `start_oauth_server`
 /// Returns the authorization code or error
 #[tauri::command]
-pub async fn wait_for_oauth_callback(port: u16, timeout_secs: u64) -> Result<OAuthCallbackResult, String> {
-    let addr = format!("127.0.0.1:{}", port);
-
-    let listener = TcpListener::bind(&addr)
+pub async fn wait_for_oauth_callback(
+    session_id: String,
+    timeout_secs: u64,
+    expected_state: String,
+    registry: tauri::State<'_, OAuthListenerRegistry>,
+) -> Result<OAuthCallbackResult, String> {
+    let OAuthListenerEntry {
+        listener,
+        tls_acceptor,
+        theme,
+        templates,
+        max_request_bytes,
+        created_at: _,
+    } = registry
+        .0
+        .lock()
         .await
-        .map_err(|e| format!("Failed to bind to {}: {}", addr, e))?;
+        .remove(&session_id)
+        .ok_or("No OAuth server session found for this session id")?;
 
-    log::info!("OAuth callback server listening on {}", addr);
+    log::info!(
+        "OAuth callback server listening on {:?}",
+        listener.local_addr()
+    );
 
     // Create a channel to signal completion
     let (tx, rx) = oneshot::channel::<OAuthCallbackResult>();
     let tx = Arc::new(tokio::sync::Mutex::new(Some(tx)));
 
-    // Spawn a task to handle the connection
+    // Tracks every per-connection handler task (see below) so they can all be
+    // aborted alongside the accept loop once the flow resolves, instead of
+    // being left to run detached in the background.
+    let connections = Arc::new(tokio::sync::Mutex::new(tokio::task::JoinSet::new()));
+
+    // Spawn a task that accepts connections and, for each one, spawns its own
+    // handler task. A connection that opens its socket and then stalls (a
+    // browser prefetch or health probe, or just a slow client) is bounded by
+    // its own `CONNECTION_TIMEOUT` and can't block the accept loop from
+    // taking the *next* connection — including the genuine callback.
     let tx_clone = Arc::clone(&tx);
+    let expected_state = expected_state.clone();
+    let connections_clone = Arc::clone(&connections);
     let handle = tokio::spawn(async move {
         loop {
             match listener.accept().await {
-                Ok((mut socket, _)) => {
-                    // Read the HTTP request
-                    let mut buffer = vec![0u8; 4096];
-                    let n = match socket.read(&mut buffer).await {
-                        Ok(n) => n,
-                        Err(e) => {
-                            log::error!("Failed to read from socket: {}", e);
-                            continue;
-                        }
-                    };
-
-                    let request = String::from_utf8_lossy(&buffer[..n]);
-                    log::debug!("Received OAuth callback request: {}", request);
-
-                    // Parse the request to extract the path and query string
-                    let result = parse_oauth_callback(&request);
-
-                    // Send HTML response
-                    let (status, body) = if result.code.is_some() {
-                        ("200 OK", get_success_html())
-                    } else {
-                        ("400 Bad Request", get_error_html(result.error.as_deref().unwrap_or("Unknown error")))
-                    };
-
-                    let response = format!(
-                        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
-                        status,
-                        body.len(),
-                        body
-                    );
-
-                    if let Err(e) = socket.write_all(response.as_bytes()).await {
-                        log::error!("Failed to write response: {}", e);
-                    }
-                    let _ = socket.shutdown().await;
+                Ok((socket, _)) => {
+                    let expected_state = expected_state.clone();
+                    let theme = theme.clone();
+                    let templates = templates.clone();
+                    let tls_acceptor = tls_acceptor.clone();
+                    let tx_clone = Arc::clone(&tx_clone);
 
-                    // Send result and exit
-                    if let Some(tx) = tx_clone.lock().await.take() {
-                        let _ = tx.send(result);
-                    }
-                    break;
+                    connections_clone.lock().await.spawn(async move {
+                        let result = match &tls_acceptor {
+                            Some(acceptor) => match tokio::time::timeout(
+                                CONNECTION_TIMEOUT,
+                                acceptor.accept(socket),
+                            )
+                            .await
+                            {
+                                Ok(Ok(tls_stream)) => {
+                                    handle_connection(
+                                        tls_stream,
+                                        &expected_state,
+                                        &theme,
+                                        &templates,
+                                        max_request_bytes,
+                                    )
+                                    .await
+                                }
+                                Ok(Err(e)) => {
+                                    log::error!("TLS handshake failed: {}", e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    log::warn!(
+                                        "TLS handshake timed out after {:?}",
+                                        CONNECTION_TIMEOUT
+                                    );
+                                    return;
+                                }
+                            },
+                            None => {
+                                handle_connection(
+                                    socket,
+                                    &expected_state,
+                                    &theme,
+                                    &templates,
+                                    max_request_bytes,
+                                )
+                                .await
+                            }
+                        };
+
+                        let Some(result) = result else {
+                            return;
+                        };
+
+                        // Send the result of the first genuine callback; any
+                        // later connection's result is simply dropped.
+                        if let Some(tx) = tx_clone.lock().await.take() {
+                            let _ = tx.send(result);
+                        }
+                    });
                 }
                 Err(e) => {
                     log::error!("Failed to accept connection: {}", e);
@@ -94,95 +475,288 @@ pub async fn wait_for_oauth_callback(port: u16, timeout_secs: u64) -> Result<OAu
     match tokio::time::timeout(timeout, rx).await {
         Ok(Ok(result)) => {
             handle.abort();
+            connections.lock().await.abort_all();
             Ok(result)
         }
         Ok(Err(_)) => {
             handle.abort();
+            connections.lock().await.abort_all();
             Err("OAuth callback channel closed unexpectedly".to_string())
         }
         Err(_) => {
             handle.abort();
+            connections.lock().await.abort_all();
             Err(format!("OAuth callback timed out after {} seconds", timeout_secs))
         }
     }
 }
 
-/// Parse the OAuth callback request and extract code or error
-fn parse_oauth_callback(request: &str) -> OAuthCallbackResult {
+/// Drop an in-progress OAuth server session, closing its listener. Lets the
+/// caller clean up immediately when a flow is abandoned (the user closes the
+/// auth window, the app restarts the flow, an error occurs before
+/// `wait_for_oauth_callback` is called) instead of waiting out `SESSION_TTL`.
+#[tauri::command]
+pub async fn cancel_oauth_server(
+    session_id: String,
+    registry: tauri::State<'_, OAuthListenerRegistry>,
+) -> Result<(), String> {
+    registry.0.lock().await.remove(&session_id);
+    Ok(())
+}
+
+/// Tokens returned by the authorization server's token endpoint (RFC 6749 section 5.1)
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OAuthTokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<u64>,
+    pub token_type: String,
+    pub scope: Option<String>,
+}
+
+/// Error body returned by the token endpoint on failure (RFC 6749 section 5.2)
+#[derive(Debug, Clone, serde::Deserialize)]
+struct OAuthErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// POST a grant request to `token_endpoint` and parse either an `OAuthTokens`
+/// success body or an RFC 6749 error body into a `Result`.
+async fn post_token_request(
+    token_endpoint: &str,
+    params: &[(&str, &str)],
+) -> Result<OAuthTokens, String> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(token_endpoint)
+        .header("Accept", "application/json")
+        .form(params)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach token endpoint: {}", e))?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read token endpoint response: {}", e))?;
+
+    if !status.is_success() {
+        if let Ok(err) = serde_json::from_str::<OAuthErrorResponse>(&body) {
+            return Err(match err.error_description {
+                Some(desc) => format!("{}: {}", err.error, desc),
+                None => err.error,
+            });
+        }
+        return Err(format!("Token endpoint returned {}: {}", status, body));
+    }
+
+    serde_json::from_str::<OAuthTokens>(&body)
+        .map_err(|e| format!("Failed to parse token endpoint response: {} (body: {})", e, body))
+}
+
+/// Exchange an authorization code for tokens using the PKCE `code_verifier`
+/// obtained from `start_oauth_server`
+#[tauri::command]
+pub async fn exchange_oauth_code(
+    code: String,
+    token_endpoint: String,
+    client_id: String,
+    redirect_uri: String,
+    code_verifier: String,
+) -> Result<OAuthTokens, String> {
+    post_token_request(
+        &token_endpoint,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("client_id", &client_id),
+            ("redirect_uri", &redirect_uri),
+            ("code_verifier", &code_verifier),
+        ],
+    )
+    .await
+}
+
+/// Exchange a refresh token for a new set of tokens
+#[tauri::command]
+pub async fn refresh_oauth_token(
+    refresh_token: String,
+    token_endpoint: String,
+    client_id: String,
+) -> Result<OAuthTokens, String> {
+    post_token_request(
+        &token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+            ("client_id", &client_id),
+        ],
+    )
+    .await
+}
+
+/// The path the real OAuth redirect lands on; anything else (favicon
+/// prefetches, health probes, stray loopback hits) is not the callback.
+const CALLBACK_PATH: &str = "/callback";
+
+/// Why a request was not recognized as the genuine OAuth callback
+enum NotCallbackReason {
+    /// The request's path isn't `CALLBACK_PATH` (or the request was malformed)
+    WrongPath,
+    /// The path matched but neither `code` nor `error` was present
+    MissingCallbackParams,
+}
+
+/// Parse the OAuth callback request and extract code or error.
+///
+/// Returns `Err(NotCallbackReason)` for anything that isn't the genuine
+/// callback (wrong path, or the right path with no `code`/`error`) so the
+/// caller can respond and keep listening instead of resolving the flow.
+/// Rejects a genuine callback (with a distinct error) if `state` is missing
+/// or does not match `expected_state`, guarding against CSRF.
+fn parse_oauth_callback(
+    request: &str,
+    expected_state: &str,
+) -> Result<OAuthCallbackResult, NotCallbackReason> {
     // Extract the first line (GET /callback?... HTTP/1.1)
     let first_line = request.lines().next().unwrap_or("");
 
     // Extract the path with query string
     let parts: Vec<&str> = first_line.split_whitespace().collect();
     if parts.len() < 2 {
-        return OAuthCallbackResult {
-            code: None,
-            error: Some("Invalid HTTP request".to_string()),
-            error_description: None,
-        };
+        return Err(NotCallbackReason::WrongPath);
     }
 
     let path = parts[1];
+    let (route, query) = match path.find('?') {
+        Some(query_start) => (&path[..query_start], &path[query_start + 1..]),
+        None => (path, ""),
+    };
+
+    if route != CALLBACK_PATH {
+        return Err(NotCallbackReason::WrongPath);
+    }
+
+    let params: std::collections::HashMap<&str, &str> = query
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            Some((parts.next()?, parts.next().unwrap_or("")))
+        })
+        .collect();
+
+    if !params.contains_key("code") && !params.contains_key("error") {
+        return Err(NotCallbackReason::MissingCallbackParams);
+    }
 
-    // Parse query string
-    if let Some(query_start) = path.find('?') {
-        let query = &path[query_start + 1..];
-        let params: std::collections::HashMap<&str, &str> = query
-            .split('&')
-            .filter_map(|pair| {
-                let mut parts = pair.splitn(2, '=');
-                Some((parts.next()?, parts.next().unwrap_or("")))
-            })
-            .collect();
-
-        // URL decode the values
-        let code = params.get("code").map(|s| urlencoding_decode(s));
-        let error = params.get("error").map(|s| urlencoding_decode(s));
-        let error_description = params.get("error_description").map(|s| urlencoding_decode(s));
-
-        OAuthCallbackResult {
-            code,
-            error,
-            error_description,
+    match params.get("state") {
+        Some(state) if constant_time_eq(&urlencoding_decode(state), expected_state) => {}
+        Some(_) => {
+            return Ok(OAuthCallbackResult {
+                code: None,
+                error: Some("State mismatch".to_string()),
+                error_description: Some(
+                    "The callback's state parameter did not match the expected value"
+                        .to_string(),
+                ),
+            });
         }
-    } else {
-        OAuthCallbackResult {
-            code: None,
-            error: Some("No query parameters in callback".to_string()),
-            error_description: None,
+        None => {
+            return Ok(OAuthCallbackResult {
+                code: None,
+                error: Some("Missing state parameter".to_string()),
+                error_description: Some(
+                    "The callback did not include a state parameter".to_string(),
+                ),
+            });
         }
     }
+
+    // URL decode the values
+    let code = params.get("code").map(|s| urlencoding_decode(s));
+    let error = params.get("error").map(|s| urlencoding_decode(s));
+    let error_description = params.get("error_description").map(|s| urlencoding_decode(s));
+
+    Ok(OAuthCallbackResult {
+        code,
+        error,
+        error_description,
+    })
 }
 
-/// Simple URL decoding (handles %XX encoding)
+/// URL decoding (handles %XX encoding). Decoded bytes are accumulated into a
+/// `Vec<u8>` and lossily converted to UTF-8 only at the end, so multi-byte
+/// percent-encoded sequences (e.g. non-ASCII `error_description` values)
+/// survive instead of being mangled byte-by-byte.
 fn urlencoding_decode(s: &str) -> String {
-    let mut result = String::with_capacity(s.len());
-    let mut chars = s.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        if c == '%' {
-            let hex: String = chars.by_ref().take(2).collect();
-            if hex.len() == 2 {
-                if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                    result.push(byte as char);
-                    continue;
+    let mut bytes: Vec<u8> = Vec::with_capacity(s.len());
+    let mut chars = s.bytes().peekable();
+
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hi = chars.next();
+            let lo = chars.next();
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => {
+                    let hex = [hi, lo];
+                    match u8::from_str_radix(std::str::from_utf8(&hex).unwrap_or(""), 16) {
+                        Ok(byte) => bytes.push(byte),
+                        Err(_) => {
+                            bytes.push(b'%');
+                            bytes.push(hi);
+                            bytes.push(lo);
+                        }
+                    }
+                }
+                (Some(hi), None) => {
+                    bytes.push(b'%');
+                    bytes.push(hi);
                 }
+                _ => bytes.push(b'%'),
             }
-            result.push('%');
-            result.push_str(&hex);
-        } else if c == '+' {
-            result.push(' ');
+        } else if b == b'+' {
+            bytes.push(b' ');
         } else {
-            result.push(c);
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Branding/theming context injected into the callback page templates
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PageTheme {
+    pub product_name: String,
+    pub gradient_start: String,
+    pub gradient_end: String,
+    pub success_color: String,
+    pub error_color: String,
+}
+
+impl Default for PageTheme {
+    fn default() -> Self {
+        Self {
+            product_name: "Apigee Workbench".to_string(),
+            gradient_start: "#1a1a2e".to_string(),
+            gradient_end: "#16213e".to_string(),
+            success_color: "#10b981".to_string(),
+            error_color: "#ef4444".to_string(),
         }
     }
+}
 
-    result
+/// Caller-supplied Handlebars templates overriding the built-in success/error
+/// pages, so deployments can brand the callback screen per caller.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PageTemplates {
+    pub success_template: Option<String>,
+    pub error_template: Option<String>,
 }
 
-/// HTML page shown on successful authentication
-fn get_success_html() -> String {
-    r#"<!DOCTYPE html>
+const DEFAULT_SUCCESS_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html>
 <head>
     <meta charset="utf-8">
@@ -191,7 +765,7 @@ fn get_success_html() -> String {
         * { margin: 0; padding: 0; box-sizing: border-box; }
         body {
             font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
-            background: linear-gradient(135deg, #1a1a2e 0%, #16213e 100%);
+            background: linear-gradient(135deg, {{theme.gradient_start}} 0%, {{theme.gradient_end}} 100%);
             min-height: 100vh;
             display: flex;
             align-items: center;
@@ -209,7 +783,7 @@ fn get_success_html() -> String {
         .icon {
             width: 80px;
             height: 80px;
-            background: #10b981;
+            background: {{theme.success_color}};
             border-radius: 50%;
             display: flex;
             align-items: center;
@@ -225,52 +799,49 @@ fn get_success_html() -> String {
     <div class="container">
         <div class="icon">✓</div>
         <h1>Authentication Successful</h1>
-        <p>You can close this window and return to Apigee Workbench.</p>
+        <p>You can close this window and return to {{theme.product_name}}.</p>
     </div>
 </body>
-</html>"#.to_string()
-}
+</html>"#;
 
-/// HTML page shown on authentication error
-fn get_error_html(error: &str) -> String {
-    format!(r#"<!DOCTYPE html>
+const DEFAULT_ERROR_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html>
 <head>
     <meta charset="utf-8">
     <title>Authentication Failed</title>
     <style>
-        * {{ margin: 0; padding: 0; box-sizing: border-box; }}
-        body {{
+        * { margin: 0; padding: 0; box-sizing: border-box; }
+        body {
             font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
-            background: linear-gradient(135deg, #1a1a2e 0%, #16213e 100%);
+            background: linear-gradient(135deg, {{theme.gradient_start}} 0%, {{theme.gradient_end}} 100%);
             min-height: 100vh;
             display: flex;
             align-items: center;
             justify-content: center;
             color: #fff;
-        }}
-        .container {{
+        }
+        .container {
             text-align: center;
             padding: 48px;
             background: rgba(255,255,255,0.05);
             border-radius: 24px;
             backdrop-filter: blur(10px);
             border: 1px solid rgba(255,255,255,0.1);
-        }}
-        .icon {{
+        }
+        .icon {
             width: 80px;
             height: 80px;
-            background: #ef4444;
+            background: {{theme.error_color}};
             border-radius: 50%;
             display: flex;
             align-items: center;
             justify-content: center;
             margin: 0 auto 24px;
             font-size: 40px;
-        }}
-        h1 {{ font-size: 24px; margin-bottom: 12px; font-weight: 600; }}
-        p {{ color: rgba(255,255,255,0.7); font-size: 14px; }}
-        .error {{ color: #fca5a5; margin-top: 16px; font-family: monospace; font-size: 12px; }}
+        }
+        h1 { font-size: 24px; margin-bottom: 12px; font-weight: 600; }
+        p { color: rgba(255,255,255,0.7); font-size: 14px; }
+        .error { color: #fca5a5; margin-top: 16px; font-family: monospace; font-size: 12px; }
     </style>
 </head>
 <body>
@@ -278,8 +849,121 @@ fn get_error_html(error: &str) -> String {
         <div class="icon">✕</div>
         <h1>Authentication Failed</h1>
         <p>Please close this window and try again.</p>
-        <p class="error">{}</p>
+        <p class="error">{{message}}</p>
     </div>
 </body>
-</html>"#, error)
+</html>"#;
+
+/// Render the HTML page shown on successful authentication
+fn get_success_html(theme: &PageTheme, templates: &PageTemplates) -> String {
+    let template = templates
+        .success_template
+        .as_deref()
+        .unwrap_or(DEFAULT_SUCCESS_TEMPLATE);
+    render_page(template, theme, None)
+}
+
+/// Render the HTML page shown on authentication error, with `message` as the
+/// decoded, caller-facing error description
+fn get_error_html(theme: &PageTheme, templates: &PageTemplates, message: &str) -> String {
+    let template = templates
+        .error_template
+        .as_deref()
+        .unwrap_or(DEFAULT_ERROR_TEMPLATE);
+    render_page(template, theme, Some(message))
+}
+
+fn render_page(template: &str, theme: &PageTheme, message: Option<&str>) -> String {
+    let context = serde_json::json!({ "theme": theme, "message": message.unwrap_or("") });
+    handlebars::Handlebars::new()
+        .render_template(template, &context)
+        .unwrap_or_else(|e| {
+            log::error!("Failed to render OAuth callback page template: {}", e);
+            format!("Authentication {}", message.unwrap_or("successful"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urlencoding_decode_handles_ascii_and_plus() {
+        assert_eq!(urlencoding_decode("hello+world"), "hello world");
+        assert_eq!(urlencoding_decode("a%20b"), "a b");
+    }
+
+    #[test]
+    fn urlencoding_decode_preserves_multibyte_utf8() {
+        // U+2713 CHECK MARK, percent-encoded as the 3-byte UTF-8 sequence E2 9C 93
+        assert_eq!(urlencoding_decode("%E2%9C%93"), "\u{2713}");
+        // U+00E9 (e with acute), percent-encoded as the 2-byte UTF-8 sequence C3 A9
+        assert_eq!(urlencoding_decode("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn urlencoding_decode_leaves_invalid_escapes_intact() {
+        assert_eq!(urlencoding_decode("100%"), "100%");
+        assert_eq!(urlencoding_decode("100%2"), "100%2");
+        assert_eq!(urlencoding_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn code_verifier_is_43_to_128_unreserved_chars() {
+        let verifier = generate_code_verifier();
+        assert!(verifier.len() >= 43 && verifier.len() <= 128);
+        assert!(verifier.bytes().all(|b| UNRESERVED_CHARS.contains(&b)));
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc7636_test_vector() {
+        // https://datatracker.ietf.org/doc/html/rfc7636#appendix-B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        assert_eq!(
+            generate_code_challenge(verifier),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn parse_oauth_callback_accepts_matching_state() {
+        let request = "GET /callback?code=abc123&state=xyz HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let result = parse_oauth_callback(request, "xyz").expect("should be a genuine callback");
+        assert_eq!(result.code.as_deref(), Some("abc123"));
+        assert_eq!(result.error, None);
+    }
+
+    #[test]
+    fn parse_oauth_callback_rejects_state_mismatch() {
+        let request = "GET /callback?code=abc123&state=wrong HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let result = parse_oauth_callback(request, "expected").expect("should resolve with an error, not be ignored");
+        assert_eq!(result.code, None);
+        assert_eq!(result.error.as_deref(), Some("State mismatch"));
+    }
+
+    #[test]
+    fn parse_oauth_callback_rejects_missing_state() {
+        let request = "GET /callback?code=abc123 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let result = parse_oauth_callback(request, "expected").expect("should resolve with an error, not be ignored");
+        assert_eq!(result.code, None);
+        assert_eq!(result.error.as_deref(), Some("Missing state parameter"));
+    }
+
+    #[test]
+    fn parse_oauth_callback_ignores_wrong_path() {
+        let request = "GET /favicon.ico HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        assert!(matches!(
+            parse_oauth_callback(request, "expected"),
+            Err(NotCallbackReason::WrongPath)
+        ));
+    }
+
+    #[test]
+    fn parse_oauth_callback_ignores_callback_path_without_code_or_error() {
+        let request = "GET /callback HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        assert!(matches!(
+            parse_oauth_callback(request, "expected"),
+            Err(NotCallbackReason::MissingCallbackParams)
+        ));
+    }
 }